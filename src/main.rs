@@ -1,7 +1,82 @@
+// `lfence` before the read makes every earlier instruction finish before the
+// timestamp is taken, so work ahead of a read is not counted after it; later
+// instructions are not fenced and may start before the read. `__rdtscp` also
+// writes a core id into `aux`; we return it so a
+// sample that migrated between cores mid-measurement can be thrown away.
 #[inline]
-fn rdtscp() -> u64 {
+fn rdtscp() -> (u64, u32) {
     let mut aux = 0;
-    unsafe { core::arch::x86_64::__rdtscp(&mut aux) }
+    let tsc = unsafe {
+        core::arch::x86_64::_mm_lfence();
+        core::arch::x86_64::__rdtscp(&mut aux)
+    };
+    (tsc, aux)
+}
+
+// Any delta larger than this is assumed to be a TSC glitch (frequency scaling,
+// a virtualized clock, a missed migration) rather than real work.
+const SANITY_THRESHOLD: u64 = 1 << 32;
+
+// Delta between two reads, or None for an invalid sample: the thread migrated
+// cores between them (the two TSCs are then not comparable), or the clock ran
+// backwards / jumped forwards past the sanity threshold. `wrapping_sub` keeps a
+// backwards TSC from panicking in debug builds; the threshold then drops it.
+fn delta(before: (u64, u32), after: (u64, u32)) -> Option<u64> {
+    if before.1 != after.1 {
+        return None;
+    }
+    let d = after.0.wrapping_sub(before.0);
+    if d > SANITY_THRESHOLD {
+        return None;
+    }
+    return Some(d);
+}
+
+// Pin the current thread to a single core so the TSC it reads stays stable.
+#[cfg(target_os = "linux")]
+fn pin_to_core(cpu: usize) {
+    let mut set = [0u64; 16];
+    set[cpu / 64] |= 1 << (cpu % 64);
+    let ret: i64;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 203i64 => ret, // sched_setaffinity
+            in("rdi") 0,                    // pid 0 = current thread
+            in("rsi") core::mem::size_of_val(&set),
+            in("rdx") set.as_ptr(),
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    if ret < 0 {
+        panic!("sched_setaffinity failed: {}", ret);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_core(_cpu: usize) {}
+
+// Measure the TSC frequency against the wall clock so cycle counts can be
+// reported in nanoseconds. Returns cycles per nanosecond.
+fn calibrate() -> f64 {
+    let before = rdtscp();
+    let start = std::time::Instant::now();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let elapsed = start.elapsed();
+    let after = rdtscp();
+    let cycles = after.0.wrapping_sub(before.0) as f64;
+    return cycles / (elapsed.as_nanos() as f64);
+}
+
+// Round a measurement down to the nearest power-of-1.05 bucket, so `points`
+// keeps O(log cycles) distinct keys instead of one per distinct cycle count.
+fn bucket(measurement: u64) -> u64 {
+    if measurement == 0 {
+        return 0;
+    }
+    let log = (measurement as f64).ln() / 1.05_f64.ln();
+    1.05_f64.powf(log.floor()) as u64
 }
 
 #[derive(Clone, Debug)]
@@ -9,7 +84,9 @@ struct Bin {
     min: u64,
     max: u64,
     sum: u64,
+    sum2: f64,
     count: u64,
+    points: std::collections::HashMap<u64, u64>,
 }
 
 impl Bin {
@@ -18,7 +95,9 @@ impl Bin {
             min: u64::MAX,
             max: 0,
             sum: 0,
+            sum2: 0.0,
             count: 0,
+            points: std::collections::HashMap::new(),
         }
     }
 
@@ -26,29 +105,78 @@ impl Bin {
         self.min = std::cmp::min(self.min, measurement);
         self.max = std::cmp::max(self.max, measurement);
         self.sum += measurement;
+        self.sum2 += (measurement as f64) * (measurement as f64);
         self.count += 1;
+        *self.points.entry(bucket(measurement)).or_insert(0) += 1;
+    }
+
+    fn merge(&mut self, other: &Bin) {
+        self.min = std::cmp::min(self.min, other.min);
+        self.max = std::cmp::max(self.max, other.max);
+        self.sum += other.sum;
+        self.sum2 += other.sum2;
+        self.count += other.count;
+        for (bucket, count) in &other.points {
+            *self.points.entry(*bucket).or_insert(0) += *count;
+        }
     }
 
     fn mean(&self) -> u64 {
         return u64::div_ceil(self.sum, self.count);
     }
+
+    fn stddev(&self) -> u64 {
+        let mean = (self.sum as f64) / (self.count as f64);
+        let variance = self.sum2 / (self.count as f64) - mean * mean;
+        return variance.max(0.0).sqrt() as u64;
+    }
+
+    // Walk the buckets in order, accumulating counts until `fraction` of the
+    // samples are covered, and return that bucket's key.
+    fn percentile(&self, fraction: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let mut buckets: Vec<u64> = self.points.keys().copied().collect();
+        buckets.sort();
+        let target = (fraction * self.count as f64).ceil() as u64;
+        let mut cumulative = 0;
+        for bucket in buckets {
+            cumulative += self.points[&bucket];
+            if cumulative >= target {
+                return bucket;
+            }
+        }
+        return self.max;
+    }
 }
 
 #[derive(Debug)]
 struct Bins {
     bins: Vec<Bin>,
+    discarded: u64,
 }
 
 impl Bins {
     fn new(log_count: usize) -> Self {
         Bins {
             bins: vec![Bin::new(); log_count],
+            discarded: 0,
         }
     }
 
     fn get(&mut self, map_count: usize) -> &mut Bin {
         return &mut self.bins[(map_count as f64).log2().ceil() as usize];
     }
+
+    // Feed one measurement: a valid delta lands in the size-bucketed bin, an
+    // invalid one (None) is counted as discarded instead of corrupting stats.
+    fn record(&mut self, map_count: usize, sample: Option<u64>) {
+        match sample {
+            Some(d) => self.get(map_count).add(d),
+            None => self.discarded += 1,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -72,6 +200,17 @@ impl Metrics {
             free: Bins::new(log_count),
         }
     }
+
+    fn all(&self) -> [(&str, &Bins); 6] {
+        [
+            ("insert_miss", &self.insert_miss),
+            ("insert_hit", &self.insert_hit),
+            ("lookup_hit_all", &self.lookup_hit_all),
+            ("lookup_miss", &self.lookup_miss),
+            ("lookup_hit_one", &self.lookup_hit_one),
+            ("free", &self.free),
+        ]
+    }
 }
 
 struct XorShift64 {
@@ -93,208 +232,369 @@ impl XorShift64 {
     }
 }
 
-macro_rules! bench_one {
-    ( $map:expr, $rng:expr, $log_count:expr, $metrics:expr ) => {{
-        if $map.len() != 0 {
-            panic!("Non-empty map");
+// Prints each statistic twice: raw cycles on the left, nanoseconds (via the
+// startup calibration) after the `|` on the right.
+fn print_bins(name: &str, bins: &Bins, cycles_per_ns: f64) {
+    println!("{}  (cycles | ns, discarded {})", name, bins.discarded);
+    let row = |label: &str, f: &dyn Fn(&Bin) -> u64| {
+        print!("{:<6}=", label);
+        for bin in &bins.bins {
+            print!(" {:>8}", f(bin));
         }
+        print!("  |");
+        for bin in &bins.bins {
+            print!(" {:>8}", (f(bin) as f64 / cycles_per_ns) as u64);
+        }
+        println!("");
+    };
+    row("min", &|b| b.min);
+    row("avg", &|b| b.mean());
+    row("max", &|b| b.max);
+    row("std", &|b| b.stddev());
+    row("p50", &|b| b.percentile(0.50));
+    row("p90", &|b| b.percentile(0.90));
+    row("p99", &|b| b.percentile(0.99));
+    row("p999", &|b| b.percentile(0.999));
+}
 
-        let count = 1 << $log_count;
+// The operations the harness drives. Implementing this for a map type is all
+// it takes to benchmark it alongside the std baselines under the same workload.
+trait BenchMap<K, V> {
+    fn new() -> Self;
+    fn insert(&mut self, k: K, v: V);
+    fn get(&self, k: &K) -> Option<&V>;
+    fn len(&self) -> usize;
+}
 
-        let mut keys = vec![];
-        for _ in 0..count {
-            keys.push($rng.next());
-        }
+impl<K: Ord, V> BenchMap<K, V> for std::collections::BTreeMap<K, V> {
+    fn new() -> Self {
+        std::collections::BTreeMap::new()
+    }
+    fn insert(&mut self, k: K, v: V) {
+        std::collections::BTreeMap::insert(self, k, v);
+    }
+    fn get(&self, k: &K) -> Option<&V> {
+        std::collections::BTreeMap::get(self, k)
+    }
+    fn len(&self) -> usize {
+        std::collections::BTreeMap::len(self)
+    }
+}
 
-        let mut keys_missing = vec![];
-        for _ in 0..count {
-            keys_missing.push($rng.next());
-        }
+impl<K: std::hash::Hash + Eq, V> BenchMap<K, V> for std::collections::HashMap<K, V> {
+    fn new() -> Self {
+        std::collections::HashMap::new()
+    }
+    fn insert(&mut self, k: K, v: V) {
+        std::collections::HashMap::insert(self, k, v);
+    }
+    fn get(&self, k: &K) -> Option<&V> {
+        std::collections::HashMap::get(self, k)
+    }
+    fn len(&self) -> usize {
+        std::collections::HashMap::len(self)
+    }
+}
 
-        let mut values = vec![];
-        for i in 0..count {
-            values.push(keys[(i + 1) % count]);
-        }
+fn bench_one<M: BenchMap<u64, u64>>(
+    map: &mut M,
+    rng: &mut XorShift64,
+    log_count: usize,
+    metrics: &mut Metrics,
+) {
+    if map.len() != 0 {
+        panic!("Non-empty map");
+    }
 
-        for (k, v) in std::iter::zip(&keys, &values) {
-            let before = rdtscp();
-            $map.insert(*k, *v);
-            let after = rdtscp();
-            $metrics.insert_miss.get($map.len()).add(after - before);
-        }
+    let count = 1 << log_count;
 
-        for (k, v) in std::iter::zip(&keys, &values) {
-            let before = rdtscp();
-            $map.insert(*k, *v);
-            let after = rdtscp();
-            $metrics.insert_hit.get($map.len()).add(after - before);
-        }
+    let mut keys = vec![];
+    for _ in 0..count {
+        keys.push(rng.next());
+    }
 
-        {
-            let before = rdtscp();
-            for k in &keys {
-                let v = $map.get(k);
-                if v.is_none() {
-                    panic!("Oh no!")
-                }
-            }
-            let after = rdtscp();
-            $metrics
-                .lookup_hit_all
-                .get($map.len())
-                .add((after - before) / (count as u64));
-        }
+    let mut keys_missing = vec![];
+    for _ in 0..count {
+        keys_missing.push(rng.next());
+    }
+
+    let mut values = vec![];
+    for i in 0..count {
+        values.push(keys[(i + 1) % count]);
+    }
 
+    for (k, v) in std::iter::zip(&keys, &values) {
+        let before = rdtscp();
+        map.insert(*k, *v);
+        let after = rdtscp();
+        let len = map.len();
+        metrics.insert_miss.record(len, delta(before, after));
+    }
+
+    for (k, v) in std::iter::zip(&keys, &values) {
+        let before = rdtscp();
+        map.insert(*k, *v);
+        let after = rdtscp();
+        let len = map.len();
+        metrics.insert_hit.record(len, delta(before, after));
+    }
+
+    {
+        let before = rdtscp();
         for k in &keys {
-            let before = rdtscp();
-            let v = $map.get(k);
-            let after = rdtscp();
-            $metrics.lookup_hit_one.get($map.len()).add(after - before);
+            let v = map.get(k);
             if v.is_none() {
                 panic!("Oh no!")
             }
         }
+        let after = rdtscp();
+        metrics
+            .lookup_hit_all
+            .record(map.len(), delta(before, after).map(|d| d / (count as u64)));
+    }
+
+    for k in &keys {
+        let before = rdtscp();
+        let v = map.get(k);
+        let after = rdtscp();
+        metrics.lookup_hit_one.record(map.len(), delta(before, after));
+        if v.is_none() {
+            panic!("Oh no!")
+        }
+    }
+
+    for k in &keys_missing {
+        let before = rdtscp();
+        let v = map.get(k);
+        let after = rdtscp();
+        metrics.lookup_miss.record(map.len(), delta(before, after));
+        if v.is_some() {
+            panic!("Oh no!")
+        }
+    }
+}
 
-        for k in &keys_missing {
+fn bench<M: BenchMap<u64, u64>>(rng: &mut XorShift64, log_count: usize) -> Metrics {
+    let mut metrics = Metrics::new(log_count);
+    for log_count_one in 0..log_count {
+        for _ in 0..(1 << (log_count - log_count_one)) {
+            let mut map = M::new();
+            bench_one(&mut map, rng, log_count_one, &mut metrics);
+
+            let len = map.len();
             let before = rdtscp();
-            let v = $map.get(k);
+            drop(map);
             let after = rdtscp();
-            $metrics.lookup_miss.get($map.len()).add(after - before);
-            if v.is_some() {
-                panic!("Oh no!")
-            }
+            metrics.free.record(len, delta(before, after));
         }
-    }};
+    }
+    return metrics;
 }
 
-macro_rules! bench {
-    ( $Map:ty, $rng:expr, $log_count:expr ) => {{
-        let mut metrics = Metrics::new($log_count);
-        for log_count_one in 0..$log_count {
-            for _ in 0..(1 << ($log_count - log_count_one)) {
-                let mut map = <$Map>::new();
-                bench_one!(map, $rng, log_count_one, metrics);
-
-                let len = map.len();
-                let before = rdtscp();
-                drop(map);
-                let after = rdtscp();
-                metrics.free.get(len).add(after - before);
-            }
-        }
-        println!("insert_miss");
-        print!("min =");
-        for bin in &metrics.insert_miss.bins {
-            print!(" {:>8}", bin.min);
-        }
-        println!("");
-        print!("avg =");
-        for bin in &metrics.insert_miss.bins {
-            print!(" {:>8}", bin.mean());
-        }
-        println!("");
-        print!("max =");
-        for bin in &metrics.insert_miss.bins {
-            print!(" {:>8}", bin.max);
-        }
-        println!("");
-        println!("insert_hit");
-        print!("min =");
-        for bin in &metrics.insert_hit.bins {
-            print!(" {:>8}", bin.min);
-        }
-        println!("");
-        print!("avg =");
-        for bin in &metrics.insert_hit.bins {
-            print!(" {:>8}", bin.mean());
-        }
-        println!("");
-        print!("max =");
-        for bin in &metrics.insert_hit.bins {
-            print!(" {:>8}", bin.max);
-        }
-        println!("");
-        println!("lookup_hit_all");
-        print!("min =");
-        for bin in &metrics.lookup_hit_all.bins {
-            print!(" {:>8}", bin.min);
-        }
-        println!("");
-        print!("avg =");
-        for bin in &metrics.lookup_hit_all.bins {
-            print!(" {:>8}", bin.mean());
-        }
-        println!("");
-        print!("max =");
-        for bin in &metrics.lookup_hit_all.bins {
-            print!(" {:>8}", bin.max);
-        }
-        println!("");
-        println!("lookup_miss");
-        print!("min =");
-        for bin in &metrics.lookup_miss.bins {
-            print!(" {:>8}", bin.min);
-        }
-        println!("");
-        print!("avg =");
-        for bin in &metrics.lookup_miss.bins {
-            print!(" {:>8}", bin.mean());
-        }
-        println!("");
-        print!("max =");
-        for bin in &metrics.lookup_miss.bins {
-            print!(" {:>8}", bin.max);
-        }
-        println!("");
-        println!("lookup_hit_one");
-        print!("min =");
-        for bin in &metrics.lookup_hit_one.bins {
-            print!(" {:>8}", bin.min);
-        }
-        println!("");
-        print!("avg =");
-        for bin in &metrics.lookup_hit_one.bins {
-            print!(" {:>8}", bin.mean());
-        }
-        println!("");
-        print!("max =");
-        for bin in &metrics.lookup_hit_one.bins {
-            print!(" {:>8}", bin.max);
+fn run_all(benches: &[(&str, &dyn Fn() -> Metrics)]) -> Vec<(String, Metrics)> {
+    return benches
+        .iter()
+        .map(|(name, bench)| (name.to_string(), bench()))
+        .collect();
+}
+
+fn print_metrics(results: &[(String, Metrics)], cycles_per_ns: f64) {
+    for (name, metrics) in results {
+        println!();
+        println!("{}:", name);
+        for (metric, bins) in metrics.all() {
+            print_bins(metric, bins, cycles_per_ns);
         }
-        println!("");
-        println!("free");
-        print!("min =");
-        for bin in &metrics.free.bins {
-            print!(" {:>8}", bin.min);
+    }
+    println!();
+}
+
+// One size-bucketed statistic row, flattened for JSON/CSV export.
+#[derive(Debug)]
+struct Record {
+    map: String,
+    metric: String,
+    log2: usize,
+    count: u64,
+    min: u64,
+    max: u64,
+    mean: u64,
+    stddev: u64,
+    p50: u64,
+    p90: u64,
+    p99: u64,
+    p999: u64,
+}
+
+fn records(results: &[(String, Metrics)]) -> Vec<Record> {
+    let mut out = vec![];
+    for (map, metrics) in results {
+        for (metric, bins) in metrics.all() {
+            for (log2, bin) in bins.bins.iter().enumerate() {
+                if bin.count == 0 {
+                    continue;
+                }
+                out.push(Record {
+                    map: map.clone(),
+                    metric: metric.to_string(),
+                    log2,
+                    count: bin.count,
+                    min: bin.min,
+                    max: bin.max,
+                    mean: bin.mean(),
+                    stddev: bin.stddev(),
+                    p50: bin.percentile(0.50),
+                    p90: bin.percentile(0.90),
+                    p99: bin.percentile(0.99),
+                    p999: bin.percentile(0.999),
+                });
+            }
         }
-        println!("");
-        print!("avg =");
-        for bin in &metrics.free.bins {
-            print!(" {:>8}", bin.mean());
+    }
+    return out;
+}
+
+fn to_json(records: &[Record]) -> String {
+    let mut s = String::from("[\n");
+    for (i, r) in records.iter().enumerate() {
+        s.push_str(&format!(
+            "  {{\"map\": {:?}, \"metric\": {:?}, \"log2\": {}, \"count\": {}, \"min\": {}, \"max\": {}, \"mean\": {}, \"stddev\": {}, \"p50\": {}, \"p90\": {}, \"p99\": {}, \"p999\": {}}}{}\n",
+            r.map, r.metric, r.log2, r.count, r.min, r.max, r.mean, r.stddev, r.p50, r.p90, r.p99, r.p999,
+            if i + 1 < records.len() { "," } else { "" },
+        ));
+    }
+    s.push_str("]\n");
+    return s;
+}
+
+fn to_csv(records: &[Record]) -> String {
+    let mut s = String::from("map,metric,log2,count,min,max,mean,stddev,p50,p90,p99,p999\n");
+    for r in records {
+        s.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            r.map, r.metric, r.log2, r.count, r.min, r.max, r.mean, r.stddev, r.p50, r.p90, r.p99,
+            r.p999,
+        ));
+    }
+    return s;
+}
+
+// Pull `"key": <digits>` out of a line our own emitter produced.
+fn json_u64(line: &str, key: &str) -> Option<u64> {
+    let pat = format!("\"{}\": ", key);
+    let start = line.find(&pat)? + pat.len();
+    let rest = &line[start..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    return rest[..end].parse().ok();
+}
+
+// Pull `"key": "value"` out of a line our own emitter produced.
+fn json_str(line: &str, key: &str) -> Option<String> {
+    let pat = format!("\"{}\": \"", key);
+    let start = line.find(&pat)? + pat.len();
+    let rest = &line[start..];
+    let end = rest.find('"')?;
+    return Some(rest[..end].to_string());
+}
+
+fn load_baseline(path: &str) -> Vec<Record> {
+    let text = std::fs::read_to_string(path).expect("could not read baseline");
+    let mut out = vec![];
+    for line in text.lines() {
+        if !line.contains("\"map\"") {
+            continue;
         }
-        println!("");
-        print!("max =");
-        for bin in &metrics.free.bins {
-            print!(" {:>8}", bin.max);
+        out.push(Record {
+            map: json_str(line, "map").unwrap(),
+            metric: json_str(line, "metric").unwrap(),
+            log2: json_u64(line, "log2").unwrap() as usize,
+            count: json_u64(line, "count").unwrap(),
+            min: json_u64(line, "min").unwrap(),
+            max: json_u64(line, "max").unwrap(),
+            mean: json_u64(line, "mean").unwrap(),
+            stddev: json_u64(line, "stddev").unwrap(),
+            p50: json_u64(line, "p50").unwrap(),
+            p90: json_u64(line, "p90").unwrap(),
+            p99: json_u64(line, "p99").unwrap(),
+            p999: json_u64(line, "p999").unwrap(),
+        });
+    }
+    return out;
+}
+
+// Regression threshold: a p99 that grows by more than this fraction over the
+// baseline is flagged.
+const REGRESSION_THRESHOLD: f64 = 0.08;
+
+fn compare(current: &[Record], baseline: &[Record]) {
+    println!(
+        "{:<14} {:<14} {:>4} {:>10} {:>10} {:>8}",
+        "map", "metric", "log2", "base_p99", "cur_p99", "delta"
+    );
+    for cur in current {
+        let base = baseline
+            .iter()
+            .find(|b| b.map == cur.map && b.metric == cur.metric && b.log2 == cur.log2);
+        let base = match base {
+            Some(base) => base,
+            None => continue,
+        };
+        if base.p99 == 0 {
+            continue;
         }
-        println!("");
-    }};
+        let delta = (cur.p99 as f64 - base.p99 as f64) / (base.p99 as f64);
+        let flag = if delta > REGRESSION_THRESHOLD {
+            "  <== REGRESSION"
+        } else {
+            ""
+        };
+        println!(
+            "{:<14} {:<14} {:>4} {:>10} {:>10} {:>+7.1}%{}",
+            cur.map,
+            cur.metric,
+            cur.log2,
+            base.p99,
+            cur.p99,
+            delta * 100.0,
+            flag,
+        );
+    }
 }
 
 fn main() {
     let log_count = 17;
 
-    println!();
-
-    println!("BTreeMap:");
-    let mut rng = XorShift64::new();
-    bench!(std::collections::BTreeMap::<u64, u64>, rng, log_count);
+    pin_to_core(0);
+    let cycles_per_ns = calibrate();
+    println!("TSC: {:.3} cycles/ns", cycles_per_ns);
 
-    println!();
+    let results = run_all(&[
+        ("BTreeMap", &|| {
+            let mut rng = XorShift64::new();
+            bench::<std::collections::BTreeMap<u64, u64>>(&mut rng, log_count)
+        }),
+        ("HashMap (sip)", &|| {
+            let mut rng = XorShift64::new();
+            bench::<std::collections::HashMap<u64, u64>>(&mut rng, log_count)
+        }),
+    ]);
 
-    println!("HashMap (sip):");
-    let mut rng = XorShift64::new();
-    bench!(std::collections::HashMap::<u64, u64>, rng, log_count);
-
-    println!();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("--json") => {
+            let path = args.get(2).expect("--json needs a path");
+            std::fs::write(path, to_json(&records(&results))).expect("could not write json");
+        }
+        Some("--csv") => {
+            let path = args.get(2).expect("--csv needs a path");
+            std::fs::write(path, to_csv(&records(&results))).expect("could not write csv");
+        }
+        Some("--baseline") => {
+            let path = args.get(2).expect("--baseline needs a path");
+            compare(&records(&results), &load_baseline(path));
+        }
+        _ => print_metrics(&results, cycles_per_ns),
+    }
 }